@@ -12,9 +12,12 @@
 // You should have received a copy of the GNU Affero General Public License along with this program.
 // If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp;
 use std::collections::BitSet;
 use std::iter;
 use std::mem;
+use std::u32;
+use std::usize;
 
 use channel;
 
@@ -24,7 +27,11 @@ pub mod nodes;
 /// A synth node.
 pub trait Node {
     /// Prepare a new set of outputs from the last set of inputs provided.
-    fn run(&mut self);
+    ///
+    /// `block` is how many samples the node should produce this cycle: the block size negotiated
+    /// for its edges (see `Graph::negotiate`), clamped to the host block the graph is rendering. A
+    /// node free to ignore it (e.g. a constant source) may do so.
+    fn run(&mut self, block: usize);
 
     fn num_inputs(&self) -> usize;
 
@@ -35,6 +42,91 @@ pub trait Node {
 
     /// Get output channels.
     fn get_output<'x>(&'x self, idx: usize) -> Option<&'x channel::Out>;
+
+    /// The range of `Caps` acceptable on input `idx`.
+    ///
+    /// The default accepts anything; a node that only works at a particular sample rate or block
+    /// size should narrow the range so that negotiation can reject incompatible producers.
+    fn in_caps(&self, _idx: InputID) -> CapsRange { CapsRange::any() }
+
+    /// The range of `Caps` this node can produce on output `idx`.
+    ///
+    /// The default accepts anything; see `in_caps`.
+    fn out_caps(&self, _idx: OutputID) -> CapsRange { CapsRange::any() }
+}
+
+/// A concrete, fixed description of the samples flowing across an edge.
+#[derive(Clone, Copy, Debug)]
+pub struct Caps {
+    /// Samples per second.
+    pub sample_rate: u32,
+
+    /// The largest block, in samples, that may be pushed in one `run()`.
+    pub max_block: usize,
+}
+
+/// The sample rate negotiation settles on when both ends leave it unconstrained.
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// The block size negotiation settles on when both ends leave it unconstrained.
+pub const DEFAULT_BLOCK: usize = 1024;
+
+/// The set of `Caps` a node is willing to accept or produce on a channel, as inclusive ranges.
+#[derive(Clone, Copy, Debug)]
+pub struct CapsRange {
+    /// Lowest acceptable sample rate.
+    pub min_rate: u32,
+
+    /// Highest acceptable sample rate.
+    pub max_rate: u32,
+
+    /// Smallest acceptable block size.
+    pub min_block: usize,
+
+    /// Largest acceptable block size.
+    pub max_block: usize,
+}
+
+impl CapsRange {
+    /// A range that accepts every sample rate and block size.
+    pub fn any() -> CapsRange {
+        CapsRange {
+            min_rate:  0,
+            max_rate:  u32::MAX,
+            min_block: 0,
+            max_block: usize::MAX,
+        }
+    }
+
+    // The overlap of two ranges, or `None` if they are disjoint.
+    fn intersect(&self, other: &CapsRange) -> Option<CapsRange> {
+        let min_rate  = cmp::max(self.min_rate,  other.min_rate);
+        let max_rate  = cmp::min(self.max_rate,  other.max_rate);
+        let min_block = cmp::max(self.min_block, other.min_block);
+        let max_block = cmp::min(self.max_block, other.max_block);
+
+        if min_rate > max_rate || min_block > max_block {
+            None
+        } else {
+            Some(CapsRange {
+                min_rate:  min_rate,
+                max_rate:  max_rate,
+                min_block: min_block,
+                max_block: max_block,
+            })
+        }
+    }
+
+    // Pick a single concrete `Caps` from the range. We aim for the sensible defaults and only move
+    // off them when the range forces us to, so a wide-open range (both ends unconstrained) fixates
+    // to `DEFAULT_SAMPLE_RATE`/`DEFAULT_BLOCK` rather than to `u32::MAX`/`usize::MAX`, which would be
+    // a nonsense "agreed" buffer length.
+    fn fixate(&self) -> Caps {
+        Caps {
+            sample_rate: cmp::max(self.min_rate,  cmp::min(self.max_rate,  DEFAULT_SAMPLE_RATE)),
+            max_block:   cmp::max(self.min_block, cmp::min(self.max_block, DEFAULT_BLOCK)),
+        }
+    }
 }
 
 /// A descriptor used to refer to a node within a particular graph.
@@ -56,12 +148,18 @@ struct NodeWrapper<'x> {
     //
     // Unused outputs are fine, but unused inputs are not.
     inputs: Vec<Option<(NodeID, OutputID)>>,
+
+    // The `Caps` fixed on each input by the last `negotiate`, indexed by InputID. `None` means the
+    // input hasn't been negotiated (or isn't patched).
+    in_caps: Vec<Option<Caps>>,
 }
 
 /// A complete audio pipeline. This is a directed multi-graph, and is required to be acyclic.
 pub struct Graph<'x> {
-    // The nodes of the graph.
-    nodes: Vec<NodeWrapper<'x>>,
+    // The nodes of the graph, indexed by NodeID. A removed node leaves a `None` tombstone behind
+    // rather than shifting the others down, so every NodeID handed out by `add_node` stays valid
+    // for the life of the graph (a handle to a removed node simply reports `NoSuchNode`).
+    nodes: Vec<Option<NodeWrapper<'x>>>,
 
     // A list of NodeIDs, in dependency order.
     order: Vec<NodeID>,
@@ -87,12 +185,17 @@ impl<'x> Graph<'x> {
             .take(n.num_inputs())
             .collect() ;
 
+        let in_caps = iter::repeat(None)
+            .take(n.num_inputs())
+            .collect() ;
+
         let id = self.nodes.len();
 
-        self.nodes.push( NodeWrapper {
-            node:   box n,
-            inputs: inputs,
-        });
+        self.nodes.push( Some(NodeWrapper {
+            node:    box n,
+            inputs:  inputs,
+            in_caps: in_caps,
+        }));
 
         // The order is no longer valid, since we've added a new node.
         self.dirty = true;
@@ -122,18 +225,21 @@ impl<'x> Graph<'x> {
 
         use self::Error::*;
 
-        match self.nodes.get(o_node) {
+        match self.nodes.get(o_node).and_then(|slot| slot.as_ref()) {
             Some(nw) => if nw.node.num_outputs() <= o_chan {
                 return Err(NoSuchOutput(o_node, o_chan))
             },
             None     => return Err(NoSuchNode(o_node)),
         }
 
-        let nw = match self.nodes.get_mut(i_node) {
+        let nw = match self.nodes.get_mut(i_node).and_then(|slot| slot.as_mut()) {
             Some(nw) => nw,
             None     => return Err(NoSuchNode(i_node)),
         };
 
+        // The order is no longer valid, since an edge is being added or replaced.
+        self.dirty = true;
+
         let old = match nw.inputs.get_mut(i_chan) {
             Some(field) => mem::replace(field, Some((o_node, o_chan))),
             None        => return Err(NoSuchInput(i_node, i_chan)),
@@ -145,6 +251,90 @@ impl<'x> Graph<'x> {
         }
     }
 
+    /// Disconnect whatever is patched into input `i_chan` of `i_node`, leaving the input empty.
+    ///
+    /// This marks the graph `dirty`, since the schedule may no longer be valid. Unpatching an
+    /// already-empty input is not an error.
+    ///
+    /// # Errors
+    ///
+    /// `Error::NoSuchNode` if `i_node` doesn't refer to a live node, or `Error::NoSuchInput` if
+    /// `i_chan` isn't one of its inputs.
+    pub fn unpatch(&mut self, i_node: NodeID, i_chan: InputID) -> Result<()> {
+        {
+            let nw = match self.nodes.get_mut(i_node).and_then(|slot| slot.as_mut()) {
+                Some(nw) => nw,
+                None     => return Err(Error::NoSuchNode(i_node)),
+            };
+
+            match nw.inputs.get_mut(i_chan) {
+                Some(field) => *field = None,
+                None        => return Err(Error::NoSuchInput(i_node, i_chan)),
+            }
+
+            nw.in_caps[i_chan] = None;
+        }
+
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Delete a node from the graph.
+    ///
+    /// The node's slot becomes a tombstone — its `NodeID` is not reused, so every other handle
+    /// stays valid. Any input elsewhere that was fed by this node is auto-unpatched, leaving the
+    /// graph consistent, and the graph is marked `dirty`.
+    ///
+    /// # Errors
+    ///
+    /// `Error::NoSuchNode` if `id` doesn't refer to a live node.
+    pub fn remove_node(&mut self, id: NodeID) -> Result<()> {
+        match self.nodes.get(id).and_then(|slot| slot.as_ref()) {
+            Some(..) => {},
+            None     => return Err(Error::NoSuchNode(id)),
+        }
+
+        self.nodes[id] = None;
+
+        // Auto-unpatch every input that was reading from the removed node.
+        for slot in self.nodes.iter_mut() {
+            if let Some(ref mut nw) = *slot {
+                for i_chan in 0..nw.inputs.len() {
+                    let feeds_removed = match nw.inputs[i_chan] {
+                        Some((src, _)) => src == id,
+                        None           => false,
+                    };
+
+                    if feeds_removed {
+                        nw.inputs[i_chan]  = None;
+                        nw.in_caps[i_chan] = None;
+                    }
+                }
+            }
+        }
+
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    /// Enumerate the edges feeding node `id`, as `(input, source node, source output)` triples, so
+    /// an editor can draw them. A node that doesn't exist yields an empty iterator.
+    pub fn connections(&self, id: NodeID) -> ::std::vec::IntoIter<(InputID, NodeID, OutputID)> {
+        let mut edges = vec![];
+
+        if let Some(nw) = self.nodes.get(id).and_then(|slot| slot.as_ref()) {
+            for (i_chan, input) in nw.inputs.iter().enumerate() {
+                if let Some((src, src_chan)) = *input {
+                    edges.push((i_chan, src, src_chan));
+                }
+            }
+        }
+
+        edges.into_iter()
+    }
+
     /// This performs a topological sort of the nodes in the graph to determine the order in which
     /// the nodes will do their processing (to ensure that each `Node` has had its inputs computed
     /// before it runs).
@@ -155,7 +345,7 @@ impl<'x> Graph<'x> {
         self.order.clear();
 
         for id in 0..self.nodes.len() {
-            if !marked.contains(&id) {
+            if self.nodes[id].is_some() && !marked.contains(&id) {
                 try!(self.topo_sort_visit(&mut marked, &mut on_stack, id));
             }
         }
@@ -174,7 +364,7 @@ impl<'x> Graph<'x> {
             return Err(Error::CycleDetected);
         } else if !marked.contains(&id) {
             // This is... annoying.
-            let inputs = self.nodes[id].inputs.clone();
+            let inputs = self.nodes[id].as_ref().unwrap().inputs.clone();
 
             for input in inputs {
                 match input {
@@ -190,6 +380,248 @@ impl<'x> Graph<'x> {
         on_stack.remove(&id);
         Ok(())
     }
+
+    /// Pull `frames` samples through the whole pipeline.
+    ///
+    /// The nodes are visited in dependency order, so that every producer has `run()` before any of
+    /// its consumers read from it. For each node we first fill its patched inputs from the outputs
+    /// that feed them, then `run()` it on that fresh data. An output fanned out to several inputs is
+    /// simply read once per consumer.
+    ///
+    /// # Errors
+    ///
+    /// Propagates `Error::CapsMismatch` from the `negotiate` pass, and any error from
+    /// `compute_order` if the `order` has to be recomputed because the graph is `dirty`.
+    pub fn process(&mut self, frames: usize) -> Result<()> {
+        // Renegotiate only when the topology changed. `negotiate` recomputes the order, fixes a
+        // block size on every edge, and clears `dirty`; on an unchanged graph we reuse the cached
+        // schedule and caps, so the render path does no per-block allocation or renegotiation.
+        if self.dirty {
+            try!(self.negotiate());
+        }
+
+        // Iterate the cached schedule by index: `self.order[idx]` and the input slots are `Copy`, so
+        // nothing is cloned while `copy_edge` borrows `self.nodes` mutably.
+        for idx in 0..self.order.len() {
+            let id    = self.order[idx];
+            let block = self.block_for(id, frames);
+
+            let n_in = self.nodes[id].as_ref().unwrap().inputs.len();
+
+            for i_chan in 0..n_in {
+                let input = self.nodes[id].as_ref().unwrap().inputs[i_chan];
+                if let Some((src_node, src_chan)) = input {
+                    copy_edge(&mut self.nodes, src_node, src_chan, id, i_chan, block);
+                }
+            }
+
+            self.nodes[id].as_mut().unwrap().node.run(block);
+        }
+
+        Ok(())
+    }
+
+    // The number of samples node `id` should process this cycle: the smallest block size negotiated
+    // across its patched inputs, never larger than the host block `frames`. A node with no
+    // negotiated inputs just gets `frames`.
+    fn block_for(&self, id: NodeID, frames: usize) -> usize {
+        let nw = self.nodes[id].as_ref().unwrap();
+
+        let mut block = frames;
+
+        for caps in nw.in_caps.iter() {
+            if let Some(c) = *caps {
+                if c.max_block < block {
+                    block = c.max_block;
+                }
+            }
+        }
+
+        block
+    }
+
+    /// Negotiate a concrete `Caps` for every patched edge.
+    ///
+    /// Walking in dependency order, each producer output range is intersected with the consumer
+    /// input range that reads it, and the overlap is fixed to a single `Caps` stored on the
+    /// consumer's `NodeWrapper`. The execution engine can then hand the agreed block size to the
+    /// node when it runs.
+    ///
+    /// # Errors
+    ///
+    /// `Error::CapsMismatch(node, input)` is returned when a producer and consumer have no `Caps`
+    /// in common. Also propagates any error from `compute_order` if the graph is `dirty`.
+    pub fn negotiate(&mut self) -> Result<()> {
+        if self.dirty {
+            try!(self.compute_order());
+        }
+
+        // Iterate by index so the schedule and input slots aren't cloned.
+        for idx in 0..self.order.len() {
+            let id   = self.order[idx];
+            let n_in = self.nodes[id].as_ref().unwrap().inputs.len();
+
+            for i_chan in 0..n_in {
+                let input = self.nodes[id].as_ref().unwrap().inputs[i_chan];
+
+                if let Some((src, src_chan)) = input {
+                    let producer = self.nodes[src].as_ref().unwrap().node.out_caps(src_chan);
+                    let consumer = self.nodes[id].as_ref().unwrap().node.in_caps(i_chan);
+
+                    match producer.intersect(&consumer) {
+                        Some(range) => {
+                            self.nodes[id].as_mut().unwrap().in_caps[i_chan] = Some(range.fixate())
+                        },
+                        None => return Err(Error::CapsMismatch(id, i_chan)),
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Assign each node a generation depth and bucket the schedule into generation lists. A node
+    // with no patched inputs is in generation 0; otherwise its generation is one past the deepest
+    // of its input sources. Because `order` is topological, every source has already been assigned
+    // its depth by the time we reach a node.
+    fn generations(&self) -> Vec<Vec<NodeID>> {
+        let mut depth: Vec<usize> = iter::repeat(0).take(self.nodes.len()).collect();
+        let mut levels: Vec<Vec<NodeID>> = vec![];
+
+        for &id in self.order.iter() {
+            let mut d = 0;
+
+            for input in self.nodes[id].as_ref().unwrap().inputs.iter() {
+                if let Some((src, _)) = *input {
+                    let cand = depth[src] + 1;
+                    if cand > d {
+                        d = cand;
+                    }
+                }
+            }
+
+            depth[id] = d;
+
+            while levels.len() <= d {
+                levels.push(vec![]);
+            }
+
+            levels[d].push(id);
+        }
+
+        levels
+    }
+
+    /// Pull `frames` samples through the pipeline, running mutually-independent nodes concurrently.
+    ///
+    /// The schedule is split into generations (see `generations`): nodes within one generation
+    /// never read one another's outputs, so all of them can `run()` at once. For each generation we
+    /// first fill its inputs from the already-run earlier generations (a sequential copy step), then
+    /// fan the generation's `run()`s out to `pool` and join before advancing. The only shared
+    /// mutation — filling consumer input buffers — stays in that sequential step, which keeps the
+    /// concurrent part data-race free.
+    ///
+    /// A node's `run()` must not retain references into its channels past the call: a later
+    /// generation's copy step will write through those buffers, and nothing guarantees the earlier
+    /// node is still alive on the same thread.
+    ///
+    /// # Errors
+    ///
+    /// Propagates `Error::CapsMismatch` from the `negotiate` pass, and any error from
+    /// `compute_order` if the `order` has to be recomputed because the graph is `dirty`.
+    pub fn process_parallel<P: Pool>(&mut self, frames: usize, pool: &P) -> Result<()> {
+        if self.dirty {
+            try!(self.negotiate());
+        }
+
+        let levels = self.generations();
+
+        for level in levels.iter() {
+            // Sequential copy step: fill this generation's inputs from the earlier generations,
+            // which have already run.
+            for &id in level.iter() {
+                let block = self.block_for(id, frames);
+                let n_in  = self.nodes[id].as_ref().unwrap().inputs.len();
+
+                for i_chan in 0..n_in {
+                    let input = self.nodes[id].as_ref().unwrap().inputs[i_chan];
+                    if let Some((src_node, src_chan)) = input {
+                        copy_edge(&mut self.nodes, src_node, src_chan, id, i_chan, block);
+                    }
+                }
+            }
+
+            // Then fan the generation out. Every `id` in a generation is distinct, so the raw
+            // pointers are disjoint, and `run_all` joins before we touch `self.nodes` again.
+            let mut jobs: Vec<Box<FnMut() + Send + 'x>> = Vec::with_capacity(level.len());
+
+            for &id in level.iter() {
+                let block = self.block_for(id, frames);
+                let ptr   = SendPtr(self.nodes[id].as_mut().unwrap() as *mut NodeWrapper<'x>);
+                jobs.push(box move || unsafe { (*ptr.0).node.run(block) });
+            }
+
+            pool.run_all(jobs);
+        }
+
+        Ok(())
+    }
+}
+
+/// A batch scheduler for the concurrent part of `Graph::process_parallel`.
+///
+/// An implementation runs every job, in any order and with as much concurrency as it likes, and
+/// blocks until all of them have finished — e.g. a scoped-thread fan-out.
+pub trait Pool {
+    /// Run every job in `jobs` and return once all of them have completed.
+    fn run_all<'a>(&self, jobs: Vec<Box<FnMut() + Send + 'a>>);
+}
+
+// A raw pointer to a `NodeWrapper` that we promise to use without aliasing. `process_parallel`
+// hands one of these to each job in a generation; the pointers are disjoint and the jobs are joined
+// before the pointers could dangle.
+struct SendPtr<'x>(*mut NodeWrapper<'x>);
+
+unsafe impl<'x> Send for SendPtr<'x> {}
+
+impl<'x> Clone for SendPtr<'x> {
+    fn clone(&self) -> SendPtr<'x> { SendPtr(self.0) }
+}
+
+impl<'x> Copy for SendPtr<'x> {}
+
+// Copy one edge's worth of samples: the data produced on output `src_chan` of `src` is written into
+// input `i_chan` of `dst`. The two nodes are always distinct (the graph is acyclic), so we split the
+// slice to hand out an immutable borrow of the producer alongside the mutable borrow of the
+// consumer. On a short read (`num_samples()` below `block`) the tail of the consumer's buffer is
+// zero-filled, so the node never sees stale samples past the valid prefix.
+fn copy_edge(nodes: &mut [Option<NodeWrapper>], src: NodeID, src_chan: OutputID, dst: NodeID,
+             i_chan: InputID, block: usize) {
+
+    assert!(src != dst);
+
+    if src < dst {
+        let (head, tail) = nodes.split_at_mut(dst);
+        let out = head[src].as_ref().unwrap().node.get_output(src_chan).unwrap();
+        let inp = tail[0].as_mut().unwrap().node.get_input(i_chan).unwrap();
+        fill(out, inp.input(block));
+    } else {
+        let (head, tail) = nodes.split_at_mut(src);
+        let inp = head[dst].as_mut().unwrap().node.get_input(i_chan).unwrap();
+        let out = tail[0].as_ref().unwrap().node.get_output(src_chan).unwrap();
+        fill(out, inp.input(block));
+    }
+}
+
+// Pull from `out` into `dst` and zero whatever `out` didn't write, so a short read leaves no stale
+// samples behind.
+fn fill(out: &channel::Out, dst: &mut [f32]) {
+    let written = out.output(dst);
+
+    for sample in dst[written..].iter_mut() {
+        *sample = 0.0;
+    }
 }
 
 /// Shorthand for the standard `Result` type with a `graph::Error` as the error type.
@@ -215,4 +647,91 @@ pub enum Error {
 
     /// There is a cycle in the graph, this is not allowed.
     CycleDetected,
+
+    /// The producer feeding input `InputID` of `NodeID` has no `Caps` in common with it.
+    CapsMismatch(NodeID, InputID),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel;
+
+    // A stand-in node used to drive negotiation without real channels: it reports one output and
+    // `ins` inputs, and produces a configurable maximum block on its output.
+    struct Dummy {
+        ins:   usize,
+        block: usize,
+    }
+
+    impl Node for Dummy {
+        fn run(&mut self, _block: usize) {}
+
+        fn num_inputs(&self) -> usize { self.ins }
+
+        fn get_input<'x>(&'x mut self, _idx: usize) -> Option<&'x mut channel::In> { None }
+
+        fn num_outputs(&self) -> usize { 1 }
+
+        fn get_output<'x>(&'x self, _idx: usize) -> Option<&'x channel::Out> { None }
+
+        fn out_caps(&self, _idx: OutputID) -> CapsRange {
+            let mut range = CapsRange::any();
+            range.max_block = self.block;
+            range
+        }
+    }
+
+    #[test]
+    fn intersect_overlap() {
+        let a = CapsRange { min_rate: 0,  max_rate: 100, min_block: 0,  max_block: 512 };
+        let b = CapsRange { min_rate: 50, max_rate: 200, min_block: 64, max_block: 1024 };
+
+        let r = a.intersect(&b).unwrap();
+
+        assert_eq!(r.min_rate,  50);
+        assert_eq!(r.max_rate,  100);
+        assert_eq!(r.min_block, 64);
+        assert_eq!(r.max_block, 512);
+    }
+
+    #[test]
+    fn intersect_disjoint() {
+        let a = CapsRange { min_rate: 0,   max_rate: 100, min_block: 0, max_block: 512 };
+        let b = CapsRange { min_rate: 200, max_rate: 300, min_block: 0, max_block: 512 };
+
+        assert!(a.intersect(&b).is_none());
+    }
+
+    #[test]
+    fn fixate_defaults_when_unbounded() {
+        let caps = CapsRange::any().fixate();
+
+        assert_eq!(caps.sample_rate, DEFAULT_SAMPLE_RATE);
+        assert_eq!(caps.max_block,   DEFAULT_BLOCK);
+    }
+
+    #[test]
+    fn fixate_clamps_into_range() {
+        let r = CapsRange { min_rate: 48_000, max_rate: 96_000, min_block: 0, max_block: 256 };
+
+        let caps = r.fixate();
+
+        assert_eq!(caps.sample_rate, 48_000); // default 44_100 is below the floor, clamp up
+        assert_eq!(caps.max_block,   256);    // default 1024 is above the ceiling, clamp down
+    }
+
+    #[test]
+    fn block_for_uses_negotiated_block() {
+        let mut g = Graph::new();
+        let src = g.add_node(Dummy { ins: 0, block: 256 });
+        let dst = g.add_node(Dummy { ins: 1, block: ::std::usize::MAX });
+
+        g.patch(src, 0, dst, 0).unwrap();
+        g.negotiate().unwrap();
+
+        assert_eq!(g.block_for(dst, 4096), 256);  // clamped to the negotiated edge
+        assert_eq!(g.block_for(dst, 128),  128);  // never larger than the host block
+        assert_eq!(g.block_for(src, 4096), 4096); // a source has no inputs to constrain it
+    }
 }
@@ -22,8 +22,8 @@ pub struct Constant {
 }
 
 impl graph::Node for Constant {
-    /// Running a `Constant` is a no-op.
-    fn run(&mut self) {}
+    /// Running a `Constant` is a no-op; the output is the same length regardless of `block`.
+    fn run(&mut self, _block: usize) {}
 
     /// a `constant` has no inputs.
     fn num_inputs(&self) -> usize { 0 }
@@ -0,0 +1,290 @@
+// This file is part of Gyroscope, a program and library for electronic music production.
+// Copyright (C) 2015, Sam Payson <scpayson at gmail dot com>
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the
+// GNU Affero General Public License as published by the Free Software Foundation, either version 3
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use std::collections::HashMap;
+
+use channel;
+use graph;
+
+/// A discrete symbol drawn by the chain, interpreted as a MIDI note number.
+pub type Symbol = u8;
+
+/// A control-rate source that emits frequencies drawn from a Markov model of a training sequence.
+///
+/// The model is an order-`k` chain: for each context of the last `k` symbols it records a weighted
+/// set of successors. Each `run()` draws the next symbol for the current context, shifts it into
+/// the context window, and fills the single output with the corresponding frequency. Supplying the
+/// same seed to `new` reproduces the same sequence.
+pub struct MarkovSeq {
+    // For each length-`order` context, the observed successors and how often each was seen.
+    model: HashMap<Vec<Symbol>, Vec<(Symbol, u32)>>,
+
+    // The distribution over every symbol in the training data, used when a context has no recorded
+    // successors.
+    global: Vec<(Symbol, u32)>,
+
+    // The last `order` symbols drawn (primed from the training data).
+    context: Vec<Symbol>,
+
+    // The order `k` of the chain.
+    order: usize,
+
+    rng: Rng,
+
+    output: Out,
+}
+
+impl MarkovSeq {
+    /// Train an order-`order` chain on `training` and prepare it to emit `block`-sample control
+    /// blocks. `seed` seeds the internal RNG so that the generated sequence is reproducible.
+    pub fn new(training: &[Symbol], order: usize, block: usize, seed: u64) -> MarkovSeq {
+        let mut model: HashMap<Vec<Symbol>, Vec<(Symbol, u32)>> = HashMap::new();
+        let mut global = vec![];
+
+        for &sym in training.iter() {
+            bump(&mut global, sym);
+        }
+
+        if training.len() > order {
+            for i in 0 .. training.len() - order {
+                let context = training[i .. i + order].to_vec();
+                let next    = training[i + order];
+
+                let succ = model.entry(context).or_insert_with(Vec::new);
+                bump(succ, next);
+            }
+        }
+
+        // Prime the context from the front of the training data. If there isn't enough data the
+        // context stays short; `model` lookups will simply miss and fall back to `global`.
+        let context = if training.len() >= order {
+            training[..order].to_vec()
+        } else {
+            training.to_vec()
+        };
+
+        let first = context.first().cloned().unwrap_or(0);
+
+        MarkovSeq {
+            model:   model,
+            global:  global,
+            context: context,
+            order:   order,
+            rng:     Rng::new(seed),
+            output:  Out { count: block, val: midi_to_freq(first) },
+        }
+    }
+
+    /// The seed the internal RNG was created with.
+    pub fn seed(&self) -> u64 { self.rng.seed }
+}
+
+impl graph::Node for MarkovSeq {
+    /// Advance the chain by one step and republish the resulting frequency.
+    fn run(&mut self, _block: usize) {
+        // Borrow the distribution in place — `self.model`/`self.global` and `self.rng` are distinct
+        // fields, so no clone is needed to satisfy the borrow checker.
+        let next = {
+            let dist = match self.model.get(&self.context) {
+                Some(succ) if !succ.is_empty() => &succ[..],
+                _                              => &self.global[..],
+            };
+
+            draw(&mut self.rng, dist)
+        };
+
+        if self.order > 0 {
+            if self.context.len() >= self.order {
+                self.context.remove(0);
+            }
+            self.context.push(next);
+        }
+
+        self.output.val = midi_to_freq(next);
+    }
+
+    /// A `MarkovSeq` has no inputs.
+    fn num_inputs(&self) -> usize { 0 }
+
+    /// this will always return `none`.
+    fn get_input<'x>(&'x mut self, _idx: usize) -> Option<&'x mut channel::In> { None }
+
+    /// A `MarkovSeq` has a single output.
+    fn num_outputs(&self) -> usize { 1 }
+
+    /// the lone output carries the current frequency.
+    fn get_output<'x>(&'x self, idx: usize) -> Option<&'x channel::Out> {
+        match idx {
+            0 => Some(&self.output as &channel::Out),
+            _ => None,
+        }
+    }
+}
+
+// Draw a symbol from a weighted distribution by walking the cumulative weights. Kept free of
+// `self` so the distribution can be borrowed straight out of `model`/`global` while `rng` is
+// borrowed mutably.
+fn draw(rng: &mut Rng, dist: &[(Symbol, u32)]) -> Symbol {
+    let total = dist.iter().fold(0, |acc, &(_, w)| acc + w);
+
+    if total == 0 {
+        return dist.first().map(|&(s, _)| s).unwrap_or(0);
+    }
+
+    let mut pick = rng.next_u32() % total;
+
+    for &(sym, w) in dist.iter() {
+        if pick < w {
+            return sym;
+        }
+        pick -= w;
+    }
+
+    // Unreachable as long as the weights sum to `total`, but fall back to the last symbol.
+    dist[dist.len() - 1].0
+}
+
+// Add one to the weight of `sym` in a weighted distribution, appending it if it's new.
+fn bump(dist: &mut Vec<(Symbol, u32)>, sym: Symbol) {
+    for entry in dist.iter_mut() {
+        if entry.0 == sym {
+            entry.1 += 1;
+            return;
+        }
+    }
+    dist.push((sym, 1));
+}
+
+// Convert a MIDI note number to its frequency in Hz (A4 = note 69 = 440 Hz).
+fn midi_to_freq(note: Symbol) -> f32 {
+    440.0 * 2f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+// A small seedable xorshift generator, so sequences don't depend on an external RNG crate.
+struct Rng {
+    seed:  u64,
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // A zero state would stick at zero, so nudge it to a fixed non-zero constant.
+        let state = if seed == 0 { 0x9E3779B97F4A7C15 } else { seed };
+        Rng { seed: seed, state: state }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        (x >> 32) as u32
+    }
+}
+
+struct Out {
+    count: usize,
+    val:   f32,
+}
+
+impl channel::Out for Out {
+    fn num_samples(&self) -> usize { self.count }
+
+    fn output(&self, dst: &mut [f32]) -> usize {
+        let upper = cmp::min(self.count, dst.len());
+
+        for fptr in dst[..upper].iter_mut() {
+            *fptr = self.val;
+        }
+
+        upper
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use graph::Node;
+
+    // Run the node `n` times and collect the frequency it publishes each cycle.
+    fn run_vals(seq: &mut MarkovSeq, n: usize) -> Vec<f32> {
+        let mut vals = vec![];
+        for _ in 0..n {
+            seq.run(0);
+            vals.push(seq.output.val);
+        }
+        vals
+    }
+
+    #[test]
+    fn deterministic_single_successor_chain() {
+        // Every context has exactly one successor, so the sequence is fixed whatever the RNG does.
+        let mut seq = MarkovSeq::new(&[60, 62, 64, 60], 1, 64, 0);
+        let got = run_vals(&mut seq, 6);
+        let want: Vec<f32> = [62u8, 64, 60, 62, 64, 60]
+            .iter()
+            .map(|&n| midi_to_freq(n))
+            .collect();
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn same_seed_reproduces_sequence() {
+        // A branching chain ([60] -> 62 or 64) means the RNG actually chooses; the same seed must
+        // replay the same draws.
+        let training = [60u8, 62, 60, 64];
+        let mut a = MarkovSeq::new(&training, 1, 2, 0xC0FFEE);
+        let mut b = MarkovSeq::new(&training, 1, 2, 0xC0FFEE);
+        assert_eq!(run_vals(&mut a, 16), run_vals(&mut b, 16));
+        assert_eq!(a.seed(), 0xC0FFEE);
+    }
+
+    #[test]
+    fn short_training_falls_back_to_global() {
+        // Training shorter than the order records no contexts, so draws fall back to the global
+        // distribution; with a single training symbol the output is pinned to it.
+        let mut seq = MarkovSeq::new(&[60], 3, 1, 7);
+        let got = run_vals(&mut seq, 4);
+        let want = vec![midi_to_freq(60); 4];
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn draw_single_entry_is_deterministic() {
+        let mut rng = Rng::new(1);
+        assert_eq!(draw(&mut rng, &[(7u8, 5u32)]), 7);
+    }
+
+    #[test]
+    fn draw_empty_distribution_is_zero() {
+        let mut rng = Rng::new(1);
+        assert_eq!(draw(&mut rng, &[]), 0);
+    }
+
+    #[test]
+    fn bump_tallies_weights() {
+        let mut dist = vec![];
+        bump(&mut dist, 5);
+        bump(&mut dist, 5);
+        bump(&mut dist, 3);
+        assert_eq!(dist, vec![(5u8, 2u32), (3u8, 1u32)]);
+    }
+
+    #[test]
+    fn midi_to_freq_a440() {
+        assert_eq!(midi_to_freq(69), 440.0);
+    }
+}
@@ -0,0 +1,19 @@
+// This file is part of Gyroscope, a program and library for electronic music production.
+// Copyright (C) 2015, Sam Payson <scpayson at gmail dot com>
+//
+// This program is free software: you can redistribute it and/or modify it under the terms of the
+// GNU Affero General Public License as published by the Free Software Foundation, either version 3
+// of the License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without
+// even the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+// Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License along with this program.
+// If not, see <http://www.gnu.org/licenses/>.
+
+/// A node whose single output holds a fixed value.
+pub mod constant;
+
+/// A node that sequences pitches from a learned Markov chain.
+pub mod markov;